@@ -2,21 +2,91 @@ use crate::usb_comm::{ReadEndpoint, UsbClient, WriteEndpoint};
 use crate::vecwrapper::VecNewtype;
 use scsi::Buffer;
 
+use std::collections::{HashMap, VecDeque};
 use std::io;
 use std::io::{BufRead, Read, Seek, SeekFrom, Write};
 
+const DEFAULT_CACHE_BLOCKS: usize = 16;
+const UNMAP_OPCODE: u8 = 0x42;
+const WRITE_SAME_16_OPCODE: u8 = 0x93;
+const READ_CAPACITY_10_OPCODE: u8 = 0x25;
+const READ_CAPACITY_16_OPCODE: u8 = 0x9e;
+const READ_CAPACITY_16_SERVICE_ACTION: u8 = 0x10;
+
+#[inline]
+fn round_up(value: u64, multiple: u64) -> u64 {
+    value.div_ceil(multiple) * multiple
+}
+
+#[inline]
+fn round_down(value: u64, multiple: u64) -> u64 {
+    value / multiple * multiple
+}
+
+/// Builds the UNMAP parameter list for a single run of `block_count` blocks
+/// starting at `first_lba`, splitting into multiple block descriptors if
+/// `block_count` exceeds `u32::MAX`.
+fn unmap_param_list(first_lba: u64, block_count: u64) -> Vec<u8> {
+    let mut descriptors = Vec::new();
+    let mut remaining = block_count;
+    let mut lba = first_lba;
+    while remaining > 0 {
+        let run = remaining.min(u32::MAX as u64);
+        descriptors.extend_from_slice(&lba.to_be_bytes());
+        descriptors.extend_from_slice(&(run as u32).to_be_bytes());
+        descriptors.extend_from_slice(&[0u8; 4]);
+        lba += run;
+        remaining -= run;
+    }
+
+    let block_descriptor_len = descriptors.len() as u16;
+    let unmap_data_len = block_descriptor_len + 6;
+
+    let mut param_list = Vec::with_capacity(8 + descriptors.len());
+    param_list.extend_from_slice(&unmap_data_len.to_be_bytes());
+    param_list.extend_from_slice(&block_descriptor_len.to_be_bytes());
+    param_list.extend_from_slice(&[0u8; 4]);
+    param_list.extend_from_slice(&descriptors);
+    param_list
+}
+
+/// Builds a 10-byte UNMAP CDB (opcode 0x42) for a parameter list of
+/// `param_list_len` bytes.
+fn unmap_cdb(param_list_len: u16) -> [u8; 10] {
+    let mut cdb = [0u8; 10];
+    cdb[0] = UNMAP_OPCODE;
+    cdb[7..9].copy_from_slice(&param_list_len.to_be_bytes());
+    cdb
+}
+
+/// Builds a 16-byte WRITE SAME(16) CDB (opcode 0x93) zeroing `block_count`
+/// blocks starting at `lba`.
+fn write_same_16_cdb(lba: u64, block_count: u32) -> [u8; 16] {
+    let mut cdb = [0u8; 16];
+    cdb[0] = WRITE_SAME_16_OPCODE;
+    cdb[2..10].copy_from_slice(&lba.to_be_bytes());
+    cdb[10..14].copy_from_slice(&block_count.to_be_bytes());
+    cdb
+}
+
+struct CachedBlock {
+    buf: VecNewtype,
+    dirty: bool,
+}
+
 pub struct OffsetScsiDevice {
     device: scsi::scsi::ScsiBlockDevice<UsbClient, VecNewtype, VecNewtype, VecNewtype>,
-    block_buffer: VecNewtype,
+    cache: HashMap<usize, CachedBlock>,
+    cache_capacity: usize,
+    recency: VecDeque<usize>,
     partition_start: usize, //bytes
     partition_idx: usize,   //bytes from partition_start
-    loaded_block_number: usize,
-    needs_flush: bool,
+    partition_len: usize,   //bytes
 }
 
 impl Drop for OffsetScsiDevice {
     fn drop(&mut self) {
-        self.flush();
+        let _ = self.flush();
     }
 }
 
@@ -24,17 +94,81 @@ impl OffsetScsiDevice {
     pub fn new(
         device: scsi::scsi::ScsiBlockDevice<UsbClient, VecNewtype, VecNewtype, VecNewtype>,
         partition_start: usize,
-    ) -> Self {
-        let block_size = device.block_size() as usize;
+    ) -> io::Result<Self> {
+        Self::with_cache_blocks(device, partition_start, DEFAULT_CACHE_BLOCKS)
+    }
+
+    pub fn with_cache_blocks(
+        mut device: scsi::scsi::ScsiBlockDevice<UsbClient, VecNewtype, VecNewtype, VecNewtype>,
+        partition_start: usize,
+        cache_blocks: usize,
+    ) -> io::Result<Self> {
+        let (last_lba, block_length) = Self::read_capacity(&mut device)?;
+        let device_len = (last_lba + 1) * block_length as u64;
+        let partition_len = device_len as usize - partition_start;
+
+        Ok(Self::with_partition_len(
+            device,
+            partition_start,
+            partition_len,
+            cache_blocks,
+        ))
+    }
 
+    pub fn with_partition_len(
+        device: scsi::scsi::ScsiBlockDevice<UsbClient, VecNewtype, VecNewtype, VecNewtype>,
+        partition_start: usize,
+        partition_len: usize,
+        cache_blocks: usize,
+    ) -> Self {
         OffsetScsiDevice {
             device,
-            block_buffer: VecNewtype::with_fake_capacity(block_size),
+            cache: HashMap::with_capacity(cache_blocks),
+            cache_capacity: cache_blocks.max(1),
+            recency: VecDeque::with_capacity(cache_blocks),
             partition_start,
             partition_idx: 0,
-            loaded_block_number: 0,
-            needs_flush: false,
+            partition_len,
+        }
+    }
+
+    fn read_capacity(
+        device: &mut scsi::scsi::ScsiBlockDevice<UsbClient, VecNewtype, VecNewtype, VecNewtype>,
+    ) -> io::Result<(u64, u32)> {
+        let mut data = [0u8; 8];
+        let cdb = [READ_CAPACITY_10_OPCODE, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        device
+            .command(&cdb, &mut data)
+            .map_err(|_e| io::Error::from(io::ErrorKind::Other))?;
+
+        let last_lba = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        let block_length = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+
+        if last_lba != u32::MAX {
+            return Ok((last_lba as u64, block_length));
         }
+
+        let mut data16 = [0u8; 32];
+        let mut cdb16 = [0u8; 16];
+        cdb16[0] = READ_CAPACITY_16_OPCODE;
+        cdb16[1] = READ_CAPACITY_16_SERVICE_ACTION;
+        cdb16[10..14].copy_from_slice(&(data16.len() as u32).to_be_bytes());
+        device
+            .command(&cdb16, &mut data16)
+            .map_err(|_e| io::Error::from(io::ErrorKind::Other))?;
+
+        let last_lba = u64::from_be_bytes(data16[0..8].try_into().unwrap());
+        let block_length = u32::from_be_bytes(data16[8..12].try_into().unwrap());
+        Ok((last_lba, block_length))
+    }
+
+    /// The length in bytes of the partition this device presents.
+    pub fn len(&self) -> u64 {
+        self.partition_len as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.partition_len == 0
     }
 
     #[inline]
@@ -43,54 +177,205 @@ impl OffsetScsiDevice {
     }
 
     #[inline]
-    fn buffered_block_raw_idx(&self) -> usize {
-        self.device.block_size() as usize * self.loaded_block_number
+    fn block_size(&self) -> usize {
+        self.device.block_size() as usize
     }
 
     #[inline]
     fn cur_block_raw_idx(&self) -> usize {
-        let rel_offset = self.raw_idx() % self.device.block_size() as usize;
+        let rel_offset = self.raw_idx() % self.block_size();
         let block_start = self.raw_idx() - rel_offset;
         block_start as usize
     }
 
     #[inline]
     fn cur_block_number(&self) -> usize {
-        self.cur_block_raw_idx() / self.device.block_size() as usize
+        self.cur_block_raw_idx() / self.block_size()
     }
 
     #[inline]
     fn offset_from_cur_block(&self) -> usize {
         self.raw_idx() - self.cur_block_raw_idx()
     }
+
+    fn touch(&mut self, block_number: usize) {
+        if let Some(pos) = self.recency.iter().position(|b| *b == block_number) {
+            self.recency.remove(pos);
+        }
+        self.recency.push_back(block_number);
+    }
+
+    fn flush_block(&mut self, block_number: usize) -> io::Result<()> {
+        let raw_idx = (block_number * self.block_size()) as u32;
+        if let Some(entry) = self.cache.get_mut(&block_number) {
+            if entry.dirty {
+                self.device
+                    .write(raw_idx, &mut entry.buf)
+                    .map_err(|_e| io::Error::from(io::ErrorKind::Other))?;
+                entry.dirty = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn evict_one(&mut self) -> io::Result<()> {
+        if let Some(lru) = self.recency.pop_front() {
+            if let Err(e) = self.flush_block(lru) {
+                self.recency.push_front(lru);
+                return Err(e);
+            }
+            self.cache.remove(&lru);
+        }
+        Ok(())
+    }
+
+    fn load_block(&mut self, block_number: usize) -> io::Result<()> {
+        if self.cache.len() >= self.cache_capacity {
+            self.evict_one()?;
+        }
+
+        let block_size = self.block_size();
+        let mut buf = VecNewtype::with_fake_capacity(block_size);
+        let raw_idx = (block_number * block_size) as u32;
+        self.device
+            .read(raw_idx, &mut buf)
+            .map_err(|e| match e.cause {
+                scsi::ErrorCause::BufferTooSmallError { expected, actual } => io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "Buffer too small: wanted {} but only have {}.",
+                        expected, actual
+                    ),
+                ),
+                e => io::Error::new(io::ErrorKind::Other, format!("Unmatched error : {:?}", e)),
+            })?;
+
+        self.cache.insert(
+            block_number,
+            CachedBlock {
+                buf,
+                dirty: false,
+            },
+        );
+        Ok(())
+    }
+
+    fn invalidate_range(&mut self, start: u64, end: u64) {
+        let block_size = self.block_size() as u64;
+        let first_block = (start / block_size) as usize;
+        let last_block = (end / block_size) as usize;
+        for block_number in first_block..last_block {
+            self.cache.remove(&block_number);
+            if let Some(pos) = self.recency.iter().position(|b| *b == block_number) {
+                self.recency.remove(pos);
+            }
+        }
+    }
+
+    fn zero_fill_unaligned(&mut self, start: u64, end: u64) -> io::Result<()> {
+        if end <= start {
+            return Ok(());
+        }
+        let saved_idx = self.partition_idx;
+        self.partition_idx = start as usize - self.partition_start;
+        let zeroes = vec![0u8; (end - start) as usize];
+        let written = Write::write(self, &zeroes)?;
+        self.partition_idx = saved_idx;
+        if written != zeroes.len() {
+            return Err(io::Error::from(io::ErrorKind::WriteZero));
+        }
+        Ok(())
+    }
+
+    fn unmap_aligned(&mut self, start: u64, end: u64) -> io::Result<()> {
+        let block_size = self.block_size() as u64;
+        let first_lba = start / block_size;
+        let block_count = (end - start) / block_size;
+
+        let mut param_list = unmap_param_list(first_lba, block_count);
+        let cdb = unmap_cdb(param_list.len() as u16);
+
+        self.device
+            .command(&cdb, &mut param_list[..])
+            .map_err(|_e| io::Error::from(io::ErrorKind::Other))?;
+        Ok(())
+    }
+
+    fn write_same_aligned(&mut self, start: u64, end: u64) -> io::Result<()> {
+        let block_size = self.block_size() as u64;
+        let mut lba = start / block_size;
+        let mut remaining = (end - start) / block_size;
+
+        let mut zero_block = vec![0u8; block_size as usize];
+        while remaining > 0 {
+            let run = remaining.min(u32::MAX as u64);
+            let cdb = write_same_16_cdb(lba, run as u32);
+
+            self.device
+                .command(&cdb, &mut zero_block[..])
+                .map_err(|_e| io::Error::from(io::ErrorKind::Other))?;
+
+            lba += run;
+            remaining -= run;
+        }
+        Ok(())
+    }
+
+    /// Marks `len` bytes starting at the current position as no longer in use,
+    /// issuing a SCSI UNMAP for whole blocks and zero-filling any partial
+    /// head/tail block via ordinary buffered writes.
+    pub fn discard(&mut self, len: u64) -> io::Result<()> {
+        let block_size = self.block_size() as u64;
+        let start = self.raw_idx() as u64;
+        let end = start + len;
+
+        let aligned_start = round_up(start, block_size).min(end);
+        let aligned_end = round_down(end, block_size).max(aligned_start);
+
+        self.zero_fill_unaligned(start, aligned_start)?;
+        if aligned_end > aligned_start {
+            self.invalidate_range(aligned_start, aligned_end);
+            self.unmap_aligned(aligned_start, aligned_end)?;
+        }
+        self.zero_fill_unaligned(aligned_end, end)?;
+
+        self.partition_idx += len as usize;
+        Ok(())
+    }
+
+    /// Zero-fills `len` bytes starting at the current position, preferring a
+    /// SCSI WRITE SAME for whole blocks and falling back to ordinary buffered
+    /// writes for any partial head/tail block.
+    pub fn write_zeroes(&mut self, len: u64) -> io::Result<()> {
+        let block_size = self.block_size() as u64;
+        let start = self.raw_idx() as u64;
+        let end = start + len;
+
+        let aligned_start = round_up(start, block_size).min(end);
+        let aligned_end = round_down(end, block_size).max(aligned_start);
+
+        self.zero_fill_unaligned(start, aligned_start)?;
+        if aligned_end > aligned_start {
+            self.invalidate_range(aligned_start, aligned_end);
+            self.write_same_aligned(aligned_start, aligned_end)?;
+        }
+        self.zero_fill_unaligned(aligned_end, end)?;
+
+        self.partition_idx += len as usize;
+        Ok(())
+    }
 }
 
 impl BufRead for OffsetScsiDevice {
     fn fill_buf(&mut self) -> io::Result<&[u8]> {
-        if self.cur_block_number() != self.loaded_block_number {
-            self.flush()?;
-            self.block_buffer.clear().map_err(|_e| {
-                (io::Error::from(io::ErrorKind::Other))
-            })?;
-        }
-        let block_idx = self.cur_block_raw_idx() as u32;
-        if self.block_buffer.is_empty() {
-            let red = self
-                .device
-                .read(block_idx, &mut self.block_buffer)
-                .map_err(|e| match e.cause {
-                    scsi::ErrorCause::BufferTooSmallError { expected, actual } => io::Error::new(
-                        io::ErrorKind::UnexpectedEof,
-                        format!(
-                            "Buffer too small: wanted {} but only have {}.",
-                            expected, actual
-                        ),
-                    ),
-                    e => io::Error::new(io::ErrorKind::Other, format!("Unmatched error : {:?}", e)),
-                })?;
-            self.loaded_block_number = self.cur_block_number();
+        let block_number = self.cur_block_number();
+        if !self.cache.contains_key(&block_number) {
+            self.load_block(block_number)?;
         }
-        Ok(&self.block_buffer.inner.as_slice()[self.offset_from_cur_block()..])
+        self.touch(block_number);
+
+        let offset = self.offset_from_cur_block();
+        Ok(&self.cache.get(&block_number).unwrap().buf.inner.as_slice()[offset..])
     }
 
     fn consume(&mut self, amt: usize) {
@@ -104,16 +389,14 @@ impl Read for OffsetScsiDevice {
 
         let mut output_idx = 0;
         while output_idx < needed_bytes {
-            let byte = {
-                let buff = self.fill_buf()?;
-                if buff.is_empty() {
-                    break;
-                }
-                buff[0]
-            };
-            output_buf[output_idx] = byte;
-            output_idx += 1;
-            self.consume(1);
+            let buff = self.fill_buf()?;
+            if buff.is_empty() {
+                break;
+            }
+            let n = std::cmp::min(output_buf.len() - output_idx, buff.len());
+            output_buf[output_idx..output_idx + n].copy_from_slice(&buff[..n]);
+            output_idx += n;
+            self.consume(n);
         }
         return Ok(output_idx);
     }
@@ -123,34 +406,45 @@ impl Write for OffsetScsiDevice {
     fn write(&mut self, to_write: &[u8]) -> io::Result<usize> {
         let mut written_idx = 0;
         while written_idx < to_write.len() {
-            self.fill_buf()?;
-            if self.block_buffer.is_empty() {
-                break;
+            let block_number = self.cur_block_number();
+            if !self.cache.contains_key(&block_number) {
+                self.load_block(block_number)?;
             }
+            self.touch(block_number);
 
             let block_offset = self.offset_from_cur_block();
-            if self.block_buffer.inner[block_offset] != to_write[written_idx] {
-                self.block_buffer.inner[block_offset] = to_write[written_idx];
-                self.needs_flush = true;
+            let entry = self.cache.get_mut(&block_number).unwrap();
+            if entry.buf.is_empty() {
+                break;
             }
-            written_idx += 1;
-            self.consume(1);
+
+            let n = std::cmp::min(
+                to_write.len() - written_idx,
+                entry.buf.inner.len() - block_offset,
+            );
+            let src = &to_write[written_idx..written_idx + n];
+            let dst = &mut entry.buf.inner[block_offset..block_offset + n];
+            if dst != src {
+                dst.copy_from_slice(src);
+                entry.dirty = true;
+            }
+            written_idx += n;
+            self.consume(n);
         }
         return Ok(written_idx);
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        if !self.needs_flush {
-            return Ok(());
+        let dirty_blocks: Vec<usize> = self
+            .cache
+            .iter()
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(block_number, _)| *block_number)
+            .collect();
+
+        for block_number in dirty_blocks {
+            self.flush_block(block_number)?;
         }
-        let raw_idx = self.buffered_block_raw_idx();
-        let _ = self
-            .device
-            .write(raw_idx as u32, &mut self.block_buffer)
-            .map_err(|_e| {
-                (io::Error::from(io::ErrorKind::Other))
-            })?;
-        self.needs_flush = false;
         Ok(())
     }
 }
@@ -163,15 +457,101 @@ impl Seek for OffsetScsiDevice {
             }
             SeekFrom::Current(off) => {
                 let absr = if off < 0 {
-                    self.partition_idx - off.abs() as usize
+                    self.partition_idx
+                        .checked_sub(off.unsigned_abs() as usize)
+                        .ok_or_else(|| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "seek before start of partition",
+                            )
+                        })?
                 } else {
-                    self.partition_idx + off.abs() as usize
+                    self.partition_idx + off as usize
                 };
 
                 self.partition_idx = absr;
                 Ok(absr as u64)
             }
-            _ => unimplemented!(),
+            SeekFrom::End(off) => {
+                let absr = self.partition_len as i64 + off;
+                if absr < 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "seek before start of partition",
+                    ));
+                }
+
+                self.partition_idx = absr as usize;
+                Ok(absr as u64)
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_up_rounds_to_next_multiple() {
+        assert_eq!(round_up(0, 512), 0);
+        assert_eq!(round_up(1, 512), 512);
+        assert_eq!(round_up(512, 512), 512);
+        assert_eq!(round_up(513, 512), 1024);
+    }
+
+    #[test]
+    fn round_down_rounds_to_previous_multiple() {
+        assert_eq!(round_down(0, 512), 0);
+        assert_eq!(round_down(511, 512), 0);
+        assert_eq!(round_down(512, 512), 512);
+        assert_eq!(round_down(1023, 512), 512);
+    }
+
+    #[test]
+    fn unmap_cdb_has_opcode_and_param_length() {
+        let cdb = unmap_cdb(24);
+        assert_eq!(cdb[0], UNMAP_OPCODE);
+        assert_eq!(&cdb[7..9], &24u16.to_be_bytes());
+    }
+
+    #[test]
+    fn unmap_param_list_encodes_single_descriptor() {
+        let param_list = unmap_param_list(10, 5);
+
+        let unmap_data_len = u16::from_be_bytes(param_list[0..2].try_into().unwrap());
+        let block_descriptor_len = u16::from_be_bytes(param_list[2..4].try_into().unwrap());
+        assert_eq!(block_descriptor_len, 16);
+        assert_eq!(unmap_data_len, block_descriptor_len + 6);
+
+        let descriptor = &param_list[8..24];
+        let lba = u64::from_be_bytes(descriptor[0..8].try_into().unwrap());
+        let count = u32::from_be_bytes(descriptor[8..12].try_into().unwrap());
+        assert_eq!(lba, 10);
+        assert_eq!(count, 5);
+    }
+
+    #[test]
+    fn unmap_param_list_splits_runs_over_u32_max() {
+        let block_count = u32::MAX as u64 + 10;
+        let param_list = unmap_param_list(0, block_count);
+
+        let block_descriptor_len = u16::from_be_bytes(param_list[2..4].try_into().unwrap());
+        assert_eq!(block_descriptor_len, 32);
+
+        let first_count = u32::from_be_bytes(param_list[16..20].try_into().unwrap());
+        let second_lba = u64::from_be_bytes(param_list[24..32].try_into().unwrap());
+        let second_count = u32::from_be_bytes(param_list[32..36].try_into().unwrap());
+        assert_eq!(first_count, u32::MAX);
+        assert_eq!(second_lba, u32::MAX as u64);
+        assert_eq!(second_count, 10);
+    }
+
+    #[test]
+    fn write_same_16_cdb_has_opcode_lba_and_count() {
+        let cdb = write_same_16_cdb(0x1122_3344_5566_7788, 42);
+        assert_eq!(cdb[0], WRITE_SAME_16_OPCODE);
+        assert_eq!(&cdb[2..10], &0x1122_3344_5566_7788u64.to_be_bytes());
+        assert_eq!(&cdb[10..14], &42u32.to_be_bytes());
+    }
+}
@@ -0,0 +1,327 @@
+use crate::buf_scsi::OffsetScsiDevice;
+use crate::usb_comm::UsbClient;
+use crate::vecwrapper::VecNewtype;
+
+use std::io;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_SIGNATURE_OFFSET: usize = 510;
+const MBR_PARTITION_TABLE_OFFSET: usize = 446;
+const MBR_PARTITION_ENTRY_SIZE: usize = 16;
+const MBR_PARTITION_COUNT: usize = 4;
+const MBR_PROTECTIVE_TYPE: u8 = 0xEE;
+
+const GPT_HEADER_LBA: u32 = 1;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+type Device = scsi::scsi::ScsiBlockDevice<UsbClient, VecNewtype, VecNewtype, VecNewtype>;
+
+/// Where a partition's type came from: a one-byte DOS/MBR type code, or a
+/// 16-byte GPT partition type GUID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionType {
+    Mbr(u8),
+    Gpt([u8; 16]),
+}
+
+#[derive(Debug, Clone)]
+pub struct PartitionInfo {
+    pub partition_type: PartitionType,
+    pub name: String,
+    pub start_byte: u64,
+    pub length_byte: u64,
+}
+
+impl PartitionInfo {
+    /// Opens this partition as an `OffsetScsiDevice`, consuming the underlying device.
+    pub fn open(&self, device: Device, cache_blocks: usize) -> OffsetScsiDevice {
+        OffsetScsiDevice::with_partition_len(
+            device,
+            self.start_byte as usize,
+            self.length_byte as usize,
+            cache_blocks,
+        )
+    }
+}
+
+/// Reads LBA 0 (and the GPT header/entries if present) and returns one
+/// `PartitionInfo` per volume found on `device`.
+pub fn discover_partitions(device: &mut Device) -> io::Result<Vec<PartitionInfo>> {
+    let block_size = device.block_size() as u64;
+    let mbr = read_block(device, 0, block_size as usize)?;
+
+    if !has_mbr_signature(&mbr.inner) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no MBR signature at LBA 0",
+        ));
+    }
+
+    let entries = mbr_entries(&mbr.inner);
+    if entries
+        .first()
+        .map(|e| e.partition_type == PartitionType::Mbr(MBR_PROTECTIVE_TYPE))
+        .unwrap_or(false)
+    {
+        return read_gpt(device, block_size);
+    }
+
+    Ok(entries)
+}
+
+fn has_mbr_signature(sector: &[u8]) -> bool {
+    sector.len() >= MBR_SIGNATURE_OFFSET + 2
+        && sector[MBR_SIGNATURE_OFFSET..MBR_SIGNATURE_OFFSET + 2] == MBR_SIGNATURE
+}
+
+fn mbr_entries(mbr: &[u8]) -> Vec<PartitionInfo> {
+    let mut partitions = Vec::new();
+    for i in 0..MBR_PARTITION_COUNT {
+        let offset = MBR_PARTITION_TABLE_OFFSET + i * MBR_PARTITION_ENTRY_SIZE;
+        let entry = &mbr[offset..offset + MBR_PARTITION_ENTRY_SIZE];
+
+        let partition_type = entry[4];
+        if partition_type == 0 {
+            continue;
+        }
+
+        let lba_start = u32::from_le_bytes(entry[8..12].try_into().unwrap());
+        let sector_count = u32::from_le_bytes(entry[12..16].try_into().unwrap());
+
+        partitions.push(PartitionInfo {
+            partition_type: PartitionType::Mbr(partition_type),
+            name: String::new(),
+            start_byte: lba_start as u64 * 512,
+            length_byte: sector_count as u64 * 512,
+        });
+    }
+    partitions
+}
+
+/// Parses a GPT header, returning `(partition_entries_lba,
+/// num_partition_entries, size_of_partition_entry)`, rejecting a header
+/// with a bad signature or an implausible entry size before it can be
+/// used in further arithmetic.
+fn parse_gpt_header(header: &[u8], block_size: u64) -> io::Result<(u64, u32, u32)> {
+    if &header[0..8] != GPT_SIGNATURE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no GPT signature at LBA 1",
+        ));
+    }
+
+    let partition_entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let num_partition_entries = u32::from_le_bytes(header[80..84].try_into().unwrap());
+    let size_of_partition_entry = u32::from_le_bytes(header[84..88].try_into().unwrap());
+
+    if size_of_partition_entry < 128 || (size_of_partition_entry as u64) > block_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "implausible GPT partition entry size",
+        ));
+    }
+
+    Ok((
+        partition_entries_lba,
+        num_partition_entries,
+        size_of_partition_entry,
+    ))
+}
+
+/// Parses a single GPT partition entry, returning `None` for an unused
+/// (all-zero type GUID) slot and an error for an entry whose `ending_lba`
+/// precedes its `starting_lba`.
+fn parse_gpt_entry(entry: &[u8], block_size: u64) -> io::Result<Option<PartitionInfo>> {
+    let mut type_guid = [0u8; 16];
+    type_guid.copy_from_slice(&entry[0..16]);
+    if type_guid == [0u8; 16] {
+        return Ok(None);
+    }
+
+    let starting_lba = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+    let ending_lba = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+    if ending_lba < starting_lba {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "GPT partition entry has ending_lba before starting_lba",
+        ));
+    }
+    let name = gpt_partition_name(&entry[56..128.min(entry.len())]);
+
+    Ok(Some(PartitionInfo {
+        partition_type: PartitionType::Gpt(type_guid),
+        name,
+        start_byte: starting_lba * block_size,
+        length_byte: (ending_lba - starting_lba + 1) * block_size,
+    }))
+}
+
+fn read_gpt(device: &mut Device, block_size: u64) -> io::Result<Vec<PartitionInfo>> {
+    let header = read_block(device, GPT_HEADER_LBA, block_size as usize)?;
+    let (partition_entries_lba, num_partition_entries, size_of_partition_entry) =
+        parse_gpt_header(&header.inner, block_size)?;
+
+    let mut partitions = Vec::new();
+    let entries_per_block = block_size as u32 / size_of_partition_entry;
+    let total_blocks = num_partition_entries.div_ceil(entries_per_block);
+
+    for block_offset in 0..total_blocks {
+        let block = read_block(
+            device,
+            (partition_entries_lba + block_offset as u64) as u32,
+            block_size as usize,
+        )?;
+
+        for i in 0..entries_per_block {
+            let index = block_offset * entries_per_block + i;
+            if index >= num_partition_entries {
+                break;
+            }
+
+            let offset = (i * size_of_partition_entry) as usize;
+            let entry = &block.inner[offset..offset + size_of_partition_entry as usize];
+
+            if let Some(partition) = parse_gpt_entry(entry, block_size)? {
+                partitions.push(partition);
+            }
+        }
+    }
+
+    Ok(partitions)
+}
+
+fn gpt_partition_name(name_utf16le: &[u8]) -> String {
+    let units: Vec<u16> = name_utf16le
+        .chunks_exact(2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .take_while(|&unit| unit != 0)
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+fn read_block(device: &mut Device, lba: u32, block_size: usize) -> io::Result<VecNewtype> {
+    let mut buf = VecNewtype::with_fake_capacity(block_size);
+    let raw_idx = lba * block_size as u32;
+    device
+        .read(raw_idx, &mut buf)
+        .map_err(|_e| io::Error::from(io::ErrorKind::Other))?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mbr_sector_with_entry(partition_type: u8, lba_start: u32, sector_count: u32) -> Vec<u8> {
+        let mut sector = vec![0u8; 512];
+        let entry = &mut sector[MBR_PARTITION_TABLE_OFFSET..MBR_PARTITION_TABLE_OFFSET + 16];
+        entry[4] = partition_type;
+        entry[8..12].copy_from_slice(&lba_start.to_le_bytes());
+        entry[12..16].copy_from_slice(&sector_count.to_le_bytes());
+        sector[MBR_SIGNATURE_OFFSET..MBR_SIGNATURE_OFFSET + 2].copy_from_slice(&MBR_SIGNATURE);
+        sector
+    }
+
+    #[test]
+    fn has_mbr_signature_accepts_valid_sector() {
+        let sector = mbr_sector_with_entry(0x0c, 2048, 1024);
+        assert!(has_mbr_signature(&sector));
+    }
+
+    #[test]
+    fn has_mbr_signature_rejects_missing_signature() {
+        let mut sector = mbr_sector_with_entry(0x0c, 2048, 1024);
+        sector[MBR_SIGNATURE_OFFSET] = 0;
+        assert!(!has_mbr_signature(&sector));
+    }
+
+    #[test]
+    fn mbr_entries_parses_one_entry_and_skips_empty_slots() {
+        let sector = mbr_sector_with_entry(0x0c, 2048, 1024);
+        let entries = mbr_entries(&sector);
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].partition_type, PartitionType::Mbr(0x0c));
+        assert_eq!(entries[0].start_byte, 2048 * 512);
+        assert_eq!(entries[0].length_byte, 1024 * 512);
+    }
+
+    fn gpt_header(
+        partition_entries_lba: u64,
+        num_partition_entries: u32,
+        size_of_partition_entry: u32,
+    ) -> Vec<u8> {
+        let mut header = vec![0u8; 512];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[72..80].copy_from_slice(&partition_entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&num_partition_entries.to_le_bytes());
+        header[84..88].copy_from_slice(&size_of_partition_entry.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn parse_gpt_header_reads_fields() {
+        let header = gpt_header(2, 128, 128);
+        let (lba, count, entry_size) = parse_gpt_header(&header, 512).unwrap();
+        assert_eq!(lba, 2);
+        assert_eq!(count, 128);
+        assert_eq!(entry_size, 128);
+    }
+
+    #[test]
+    fn parse_gpt_header_rejects_bad_signature() {
+        let mut header = gpt_header(2, 128, 128);
+        header[0] = 0;
+        assert!(parse_gpt_header(&header, 512).is_err());
+    }
+
+    #[test]
+    fn parse_gpt_header_rejects_zero_entry_size() {
+        let header = gpt_header(2, 128, 0);
+        assert!(parse_gpt_header(&header, 512).is_err());
+    }
+
+    #[test]
+    fn parse_gpt_header_rejects_entry_size_larger_than_block() {
+        let header = gpt_header(2, 128, 4096);
+        assert!(parse_gpt_header(&header, 512).is_err());
+    }
+
+    fn gpt_entry(type_guid: [u8; 16], starting_lba: u64, ending_lba: u64) -> Vec<u8> {
+        let mut entry = vec![0u8; 128];
+        entry[0..16].copy_from_slice(&type_guid);
+        entry[32..40].copy_from_slice(&starting_lba.to_le_bytes());
+        entry[40..48].copy_from_slice(&ending_lba.to_le_bytes());
+        entry
+    }
+
+    #[test]
+    fn parse_gpt_entry_skips_all_zero_type_guid() {
+        let entry = gpt_entry([0u8; 16], 10, 20);
+        assert!(parse_gpt_entry(&entry, 512).unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_gpt_entry_parses_start_and_length() {
+        let entry = gpt_entry([1u8; 16], 10, 19);
+        let partition = parse_gpt_entry(&entry, 512).unwrap().unwrap();
+        assert_eq!(partition.partition_type, PartitionType::Gpt([1u8; 16]));
+        assert_eq!(partition.start_byte, 10 * 512);
+        assert_eq!(partition.length_byte, 10 * 512);
+    }
+
+    #[test]
+    fn parse_gpt_entry_rejects_ending_before_starting() {
+        let entry = gpt_entry([1u8; 16], 20, 10);
+        assert!(parse_gpt_entry(&entry, 512).is_err());
+    }
+
+    #[test]
+    fn gpt_partition_name_decodes_utf16le_and_stops_at_nul() {
+        let mut name = vec![0u8; 72];
+        for (i, unit) in "EFI".encode_utf16().enumerate() {
+            name[i * 2..i * 2 + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        assert_eq!(gpt_partition_name(&name), "EFI");
+    }
+}
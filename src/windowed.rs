@@ -0,0 +1,93 @@
+use crate::buf_scsi::OffsetScsiDevice;
+
+use std::cell::RefCell;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::rc::Rc;
+
+/// A read-only, `Clone`-able cursor over a byte range of a shared
+/// `OffsetScsiDevice`, so multiple readers can walk different regions of
+/// one drive concurrently while sharing its block cache.
+pub struct WindowedReader {
+    shared: Rc<RefCell<OffsetScsiDevice>>,
+    window_start: usize,
+    window_len: usize,
+    pos: usize,
+}
+
+impl OffsetScsiDevice {
+    /// Wraps this device in a shared window so multiple independent cursors
+    /// can be minted over it via `WindowedReader::window`.
+    pub fn window(self, start: usize, len: usize) -> WindowedReader {
+        WindowedReader::new(Rc::new(RefCell::new(self)), start, len)
+    }
+}
+
+impl WindowedReader {
+    fn new(shared: Rc<RefCell<OffsetScsiDevice>>, window_start: usize, window_len: usize) -> Self {
+        WindowedReader {
+            shared,
+            window_start,
+            window_len,
+            pos: 0,
+        }
+    }
+
+    /// Mints another window over the same underlying device, with its own
+    /// independent logical cursor.
+    pub fn window(&self, start: usize, len: usize) -> WindowedReader {
+        WindowedReader::new(self.shared.clone(), start, len)
+    }
+}
+
+impl Clone for WindowedReader {
+    fn clone(&self) -> Self {
+        WindowedReader {
+            shared: self.shared.clone(),
+            window_start: self.window_start,
+            window_len: self.window_len,
+            pos: self.pos,
+        }
+    }
+}
+
+impl Read for WindowedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.window_len.saturating_sub(self.pos);
+        let want = buf.len().min(remaining);
+        if want == 0 {
+            return Ok(0);
+        }
+
+        let mut device = self.shared.borrow_mut();
+        let saved_pos = device.seek(SeekFrom::Current(0))?;
+        device.seek(SeekFrom::Start((self.window_start + self.pos) as u64))?;
+        let result = device.read(&mut buf[..want]);
+        let restore = device.seek(SeekFrom::Start(saved_pos));
+
+        let read = result?;
+        restore?;
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+impl Seek for WindowedReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(off) => off as i64,
+            SeekFrom::Current(off) => self.pos as i64 + off,
+            SeekFrom::End(off) => self.window_len as i64 + off,
+        };
+
+        if new_pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before start of window",
+            ));
+        }
+
+        self.pos = new_pos as usize;
+        Ok(self.pos as u64)
+    }
+}